@@ -1,14 +1,31 @@
 pub use core_foundation_sys::filedescriptor::*;
 
+use bitflags::bitflags;
+
 use core_foundation_sys::base::{Boolean, CFIndex, CFRelease};
 use core_foundation_sys::base::{kCFAllocatorDefault, CFOptionFlags};
 
 use base::TCFType;
-use runloop::CFRunLoopSource;
+use core_foundation_sys::runloop::CFRunLoopMode;
+use runloop::{CFRunLoop, CFRunLoopSource};
 
 use std::mem;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::ptr;
+use std::sync::mpsc::{self, Receiver};
+
+bitflags! {
+    /// Which kinds of activity on a `CFFileDescriptor` should trigger a callback.
+    ///
+    /// Wraps the raw `kCFFileDescriptorReadCallBack`/`kCFFileDescriptorWriteCallBack`
+    /// flags so callers match on `READ_CALLBACK`/`WRITE_CALLBACK` instead of masking
+    /// bits by hand.
+    pub struct CallbackTypes: CFOptionFlags {
+        const READ_CALLBACK = kCFFileDescriptorReadCallBack;
+        const WRITE_CALLBACK = kCFFileDescriptorWriteCallBack;
+    }
+}
 
 pub struct CFFileDescriptor(CFFileDescriptorRef);
 
@@ -42,6 +59,68 @@ impl CFFileDescriptor {
         }
     }
 
+    /// Creates a `CFFileDescriptor` driven by a safe Rust closure, rather than a raw
+    /// `extern "C"` callout and hand-rolled context.
+    ///
+    /// The closure is boxed and its pointer stashed in the context's `info` field; CF
+    /// takes ownership of that box through the context's `release` callback (no `retain`
+    /// callback is installed), so it is only ever freed when Core Foundation releases the
+    /// descriptor (or creation fails).
+    pub fn with_callback<F>(fd: RawFd, close_on_invalidate: bool, callback: F) -> Option<CFFileDescriptor>
+        where F: FnMut(&CFFileDescriptor, CallbackTypes) + 'static
+    {
+        let info = Box::into_raw(Box::new(callback)) as *mut c_void;
+        let context = CFFileDescriptorContext {
+            version: 0,
+            info,
+            retain: None,
+            release: Some(release_callback::<F>),
+            copyDescription: None,
+        };
+
+        let fd_ref = unsafe {
+            CFFileDescriptorCreate(kCFAllocatorDefault,
+                                   fd,
+                                   close_on_invalidate as Boolean,
+                                   callback_trampoline::<F>,
+                                   &context)
+        };
+
+        if fd_ref.is_null() {
+            // CF never took ownership of the context, so we must free it ourselves.
+            unsafe { drop(Box::from_raw(info as *mut F)) };
+            None
+        } else {
+            Some(unsafe { TCFType::wrap_under_create_rule(fd_ref) })
+        }
+    }
+
+    /// Creates a `CFFileDescriptor` that takes ownership of `fd`, handing Core
+    /// Foundation the fd exactly once with `closeOnInvalidate` forced to `true` so
+    /// ownership is never ambiguous. Accepts anything that converts to a raw fd, e.g.
+    /// `TcpStream`, `File`, or `OwnedFd`.
+    pub fn with_owned_callback<T, F>(fd: T, callback: F) -> Option<CFFileDescriptor>
+        where T: IntoRawFd,
+              F: FnMut(&CFFileDescriptor, CallbackTypes) + 'static
+    {
+        let raw_fd = fd.into_raw_fd();
+        let cf_fd = CFFileDescriptor::with_callback(raw_fd, true, callback);
+        if cf_fd.is_none() {
+            // `with_callback` failed, so CF never took ownership of `raw_fd` either;
+            // close it ourselves rather than leaking the caller's resource.
+            unsafe { drop(::std::fs::File::from_raw_fd(raw_fd)) };
+        }
+        cf_fd
+    }
+
+    /// Returns the native descriptor CF is wrapping, or `-1` if this `CFFileDescriptor`
+    /// has already been invalidated.
+    pub fn native_descriptor(&self) -> CFFileDescriptorNativeDescriptor {
+        unsafe {
+            CFFileDescriptorGetNativeDescriptor(self.0)
+        }
+    }
+
     pub fn context(&self) -> CFFileDescriptorContext {
         unsafe {
             let mut context: CFFileDescriptorContext = mem::uninitialized();
@@ -50,13 +129,27 @@ impl CFFileDescriptor {
         }
     }
 
-    pub fn enable_callbacks(&self, callback_types: CFOptionFlags) {
+    pub fn enable_callbacks(&self, callback_types: CallbackTypes) {
+        unsafe {
+            CFFileDescriptorEnableCallBacks(self.0, callback_types.bits())
+        }
+    }
+
+    pub fn disable_callbacks(&self, callback_types: CallbackTypes) {
+        unsafe {
+            CFFileDescriptorDisableCallBacks(self.0, callback_types.bits())
+        }
+    }
+
+    #[deprecated(note = "use enable_callbacks with CallbackTypes instead")]
+    pub fn enable_callbacks_raw(&self, callback_types: CFOptionFlags) {
         unsafe {
             CFFileDescriptorEnableCallBacks(self.0, callback_types)
         }
     }
 
-    pub fn disable_callbacks(&self, callback_types: CFOptionFlags) {
+    #[deprecated(note = "use disable_callbacks with CallbackTypes instead")]
+    pub fn disable_callbacks_raw(&self, callback_types: CFOptionFlags) {
         unsafe {
             CFFileDescriptorDisableCallBacks(self.0, callback_types)
         }
@@ -91,10 +184,87 @@ impl CFFileDescriptor {
 }
 
 impl AsRawFd for CFFileDescriptor {
+    /// Returns the underlying fd, or `-1` if this `CFFileDescriptor` has been
+    /// invalidated and CF has already released it. Callers that might race an
+    /// `invalidate()` should check `valid()`, or use `native_descriptor()` directly,
+    /// rather than handing a possibly-stale `-1` fd to `libc`.
     fn as_raw_fd(&self) -> RawFd {
+        self.native_descriptor()
+    }
+}
+
+extern "C" fn callback_trampoline<F>(fd_ref: CFFileDescriptorRef,
+                                      callback_types: CFOptionFlags,
+                                      info: *mut c_void)
+    where F: FnMut(&CFFileDescriptor, CallbackTypes) + 'static
+{
+    let closure: &mut F = unsafe { &mut *(info as *mut F) };
+    // Borrow `fd_ref` without taking a retain: the "create rule" wrapper performs no
+    // `CFRetain`, so `mem::forget`ing it below is a true zero-net-effect borrow rather
+    // than a leaked reference per callback firing.
+    let fd: CFFileDescriptor = unsafe { TCFType::wrap_under_create_rule(fd_ref) };
+    closure(&fd, CallbackTypes::from_bits_truncate(callback_types));
+    mem::forget(fd);
+}
+
+extern "C" fn release_callback<F>(info: *const c_void)
+    where F: FnMut(&CFFileDescriptor, CallbackTypes) + 'static
+{
+    unsafe { drop(Box::from_raw(info as *mut F)) };
+}
+
+/// Adapts a `CFFileDescriptor` into a readiness source for async reactors built on top
+/// of this crate. The source is registered on a caller-supplied `CFRunLoop`, and
+/// readiness notifications are delivered only while that run loop is run in the
+/// registered mode (e.g. with `CFRunLoop::run_in_mode`); without a running loop no
+/// events arrive on the channel.
+///
+/// Core Foundation disables a callback type as soon as it fires once, so the internal
+/// trampoline re-enables `callback_types` on every wakeup, giving the level-triggered
+/// semantics async runtimes expect. Readiness is surfaced through a channel rather than
+/// a C callout; the source is invalidated when dropped.
+pub struct CFFileDescriptorReactorSource {
+    fd: CFFileDescriptor,
+    _source: CFRunLoopSource,
+}
+
+impl CFFileDescriptorReactorSource {
+    /// Creates the descriptor, arms `callback_types`, and registers it on `run_loop`
+    /// under `run_loop_mode`. Each readiness notification is sent on the returned
+    /// `Receiver` and the requested callback types are re-armed before the closure
+    /// returns, so callers keep seeing events until they drop the source.
+    pub fn new(fd: RawFd,
+               close_on_invalidate: bool,
+               callback_types: CallbackTypes,
+               run_loop: &CFRunLoop,
+               run_loop_mode: CFRunLoopMode)
+               -> Option<(CFFileDescriptorReactorSource, Receiver<CallbackTypes>)> {
+        let (sender, receiver) = mpsc::channel();
+
+        let cf_fd = CFFileDescriptor::with_callback(fd, close_on_invalidate, move |fd, observed| {
+            fd.enable_callbacks(callback_types);
+            let _ = sender.send(observed);
+        })?;
+
+        let source = cf_fd.to_run_loop_source(0)?;
         unsafe {
-            CFFileDescriptorGetNativeDescriptor(self.0)
+            run_loop.add_source(&source, run_loop_mode);
         }
+        cf_fd.enable_callbacks(callback_types);
+
+        Some((CFFileDescriptorReactorSource { fd: cf_fd, _source: source }, receiver))
+    }
+}
+
+impl Drop for CFFileDescriptorReactorSource {
+    fn drop(&mut self) {
+        self.fd.invalidate();
+    }
+}
+
+impl AsRawFd for CFFileDescriptorReactorSource {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
     }
 }
 
@@ -179,17 +349,17 @@ mod test {
         }
 
         info.value = 0;
-        cf_fd.enable_callbacks(kCFFileDescriptorReadCallBack);
+        cf_fd.enable_callbacks(CallbackTypes::READ_CALLBACK);
         CFRunLoop::run_current();
         assert_eq!(info.value, kCFFileDescriptorReadCallBack);
 
         info.value = 0;
-        cf_fd.enable_callbacks(kCFFileDescriptorWriteCallBack);
+        cf_fd.enable_callbacks(CallbackTypes::WRITE_CALLBACK);
         CFRunLoop::run_current();
         assert_eq!(info.value, kCFFileDescriptorWriteCallBack);
 
         info.value = 0;
-        cf_fd.disable_callbacks(kCFFileDescriptorReadCallBack | kCFFileDescriptorWriteCallBack);
+        cf_fd.disable_callbacks(CallbackTypes::READ_CALLBACK | CallbackTypes::WRITE_CALLBACK);
 
         cf_fd.invalidate();
         assert!(!cf_fd.valid());
@@ -204,4 +374,139 @@ mod test {
 
         CFRunLoop::get_current().stop();
     }
+
+    #[test]
+    fn test_with_callback_fires() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let path = CString::new("/dev/null").unwrap();
+        let raw_fd = unsafe { libc::open(path.as_ptr(), O_RDWR, 0) };
+
+        let observed = Rc::new(Cell::new(CallbackTypes::empty()));
+        let observed_in_callback = observed.clone();
+
+        let cf_fd = CFFileDescriptor::with_callback(raw_fd, true, move |_fd, callback_types| {
+            observed_in_callback.set(callback_types);
+            CFRunLoop::get_current().stop();
+        });
+        assert!(cf_fd.is_some());
+        let cf_fd = cf_fd.unwrap();
+
+        let runloop = CFRunLoop::get_current();
+        let source = cf_fd.to_run_loop_source(0);
+        assert!(source.is_some());
+        unsafe {
+            runloop.add_source(&source.unwrap(), kCFRunLoopDefaultMode);
+        }
+
+        cf_fd.enable_callbacks(CallbackTypes::READ_CALLBACK);
+        CFRunLoop::run_current();
+        assert_eq!(observed.get(), CallbackTypes::READ_CALLBACK);
+
+        cf_fd.invalidate();
+        assert!(!cf_fd.valid());
+    }
+
+    #[test]
+    fn test_with_callback_drops_closure_exactly_once() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        struct DropFlag(Rc<Cell<bool>>);
+
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                assert!(!self.0.get(), "closure dropped more than once");
+                self.0.set(true);
+            }
+        }
+
+        let path = CString::new("/dev/null").unwrap();
+        let raw_fd = unsafe { libc::open(path.as_ptr(), O_RDWR, 0) };
+
+        let dropped = Rc::new(Cell::new(false));
+        let flag = DropFlag(dropped.clone());
+
+        let cf_fd = CFFileDescriptor::with_callback(raw_fd, true, move |_fd, _callback_types| {
+            let _keep_alive = &flag;
+            unreachable!("never registered for any callbacks");
+        });
+        assert!(cf_fd.is_some());
+        assert!(!dropped.get());
+
+        drop(cf_fd);
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn test_with_owned_callback_consumes_fd() {
+        use std::fs::File;
+
+        let file = File::open("/dev/null").unwrap();
+        let raw_fd = file.as_raw_fd();
+
+        let cf_fd = CFFileDescriptor::with_owned_callback(file, |_fd, _callback_types| {
+            unreachable!();
+        });
+        assert!(cf_fd.is_some());
+        let cf_fd = cf_fd.unwrap();
+
+        assert!(cf_fd.valid());
+        assert_eq!(cf_fd.native_descriptor(), raw_fd);
+
+        // CF now owns `raw_fd` (closeOnInvalidate is forced to true), so invalidating
+        // closes it and closing it ourselves should fail.
+        cf_fd.invalidate();
+        assert!(!cf_fd.valid());
+        assert_eq!(unsafe { libc::close(raw_fd) }, -1);
+    }
+
+    #[test]
+    fn test_native_descriptor_and_as_raw_fd_after_invalidate() {
+        let path = CString::new("/dev/null").unwrap();
+        let raw_fd = unsafe { libc::open(path.as_ptr(), O_RDWR, 0) };
+        let cf_fd = CFFileDescriptor::new(raw_fd, true, never_callback, None);
+        assert!(cf_fd.is_some());
+        let cf_fd = cf_fd.unwrap();
+
+        assert_eq!(cf_fd.native_descriptor(), raw_fd);
+        assert_eq!(cf_fd.as_raw_fd(), raw_fd);
+
+        cf_fd.invalidate();
+        assert!(!cf_fd.valid());
+
+        assert_eq!(cf_fd.native_descriptor(), -1);
+        assert_eq!(cf_fd.as_raw_fd(), -1);
+    }
+
+    #[test]
+    fn test_reactor_source_rearms_and_tears_down_on_drop() {
+        use core_foundation_sys::base::CFTimeInterval;
+
+        let path = CString::new("/dev/null").unwrap();
+        let raw_fd = unsafe { libc::open(path.as_ptr(), O_RDWR, 0) };
+
+        let run_loop = CFRunLoop::get_current();
+        let (source, receiver) = CFFileDescriptorReactorSource::new(
+            raw_fd,
+            true,
+            CallbackTypes::READ_CALLBACK,
+            &run_loop,
+            kCFRunLoopDefaultMode,
+        ).expect("failed to create reactor source");
+
+        // /dev/null is always readable, so the level-triggered source should keep
+        // firing across repeated polls without the caller re-enabling callbacks itself.
+        for _ in 0..3 {
+            CFRunLoop::run_in_mode(kCFRunLoopDefaultMode, 1.0 as CFTimeInterval, true);
+            assert_eq!(receiver.recv().unwrap(), CallbackTypes::READ_CALLBACK);
+        }
+
+        // Dropping the source invalidates the descriptor and releases the boxed
+        // closure (and the `Sender` it owns), so the channel should observe
+        // disconnection rather than the leak this test guards against.
+        drop(source);
+        assert!(receiver.recv().is_err());
+    }
 }